@@ -0,0 +1,153 @@
+/// A marker or command at the start of a message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// `|b|`, which starts a new conversation, optionally with its own prompt
+    Barrier,
+    /// `|a|`, which the model never sees
+    Aside,
+    /// `|c|`, which asks the model to continue without being part of the context
+    Continue,
+    /// `|n|`, which the model sees as context but doesn't answer
+    Note,
+    /// `|dm|`, which asks for the answer in a DM
+    Dm,
+    /// `|model|`, which shows or changes the model of a channel
+    Model,
+    /// `|temp|`, which shows or changes the temperature of a channel
+    Temp,
+    /// `|window|`, which shows or changes the context window of a channel
+    Window,
+    /// `|tldr|`, which summarizes the conversation
+    Tldr,
+    /// `|fork|`, which continues the conversation in a new thread
+    Fork,
+    /// `|prompt|`, which shows or changes the prompt of a channel
+    Prompt,
+    /// `|cancel|`, which stops the completion in progress
+    Cancel,
+    /// `|enable|`, which turns the bot on
+    Enable,
+    /// `|disable|`, which turns the bot off
+    Disable,
+    /// `|export|`, which uploads the conversation as a transcript
+    Export,
+    /// `|doc|`, which stores a document as context
+    Doc,
+}
+
+impl Command {
+    /// Every command, in the order they are matched
+    const ALL: [Command; 16] = [
+        Command::Barrier,
+        Command::Aside,
+        Command::Continue,
+        Command::Note,
+        Command::Dm,
+        Command::Model,
+        Command::Temp,
+        Command::Window,
+        Command::Tldr,
+        Command::Fork,
+        Command::Prompt,
+        Command::Cancel,
+        Command::Enable,
+        Command::Disable,
+        Command::Export,
+        Command::Doc,
+    ];
+
+    /// Get the prefix that starts the command
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Command::Barrier => "|b|",
+            Command::Aside => "|a|",
+            Command::Continue => "|c|",
+            Command::Note => "|n|",
+            Command::Dm => "|dm|",
+            Command::Model => "|model|",
+            Command::Temp => "|temp|",
+            Command::Window => "|window|",
+            Command::Tldr => "|tldr|",
+            Command::Fork => "|fork|",
+            Command::Prompt => "|prompt|",
+            Command::Cancel => "|cancel|",
+            Command::Enable => "|enable|",
+            Command::Disable => "|disable|",
+            Command::Export => "|export|",
+            Command::Doc => "|doc|",
+        }
+    }
+
+    /// Parse the command at the start of a message, ignoring leading whitespace.
+    /// Returns the command and the rest of the message, trimmed.
+    pub fn parse(content: &str) -> Option<(Command, &str)> {
+        let content = content.trim_start();
+
+        Command::ALL.iter().find_map(|command| {
+            content
+                .strip_prefix(command.prefix())
+                .map(|args| (*command, args.trim()))
+        })
+    }
+
+    /// Check whether the command is handled by the bot, rather than being a marker
+    /// on a message of the conversation. Commands are never sent to the model.
+    pub fn is_bot_command(self) -> bool {
+        !matches!(
+            self,
+            Command::Barrier
+                | Command::Aside
+                | Command::Continue
+                | Command::Note
+                | Command::Dm
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Command;
+
+    #[test]
+    fn parses_marker_with_args() {
+        assert_eq!(
+            Command::parse("|b| Be brief"),
+            Some((Command::Barrier, "Be brief"))
+        );
+        assert_eq!(Command::parse("|b|é"), Some((Command::Barrier, "é")));
+    }
+
+    #[test]
+    fn incomplete_marker_before_multibyte_char_is_not_a_command() {
+        assert_eq!(Command::parse("|bé"), None);
+        assert_eq!(Command::parse("|b👍|"), None);
+    }
+
+    #[test]
+    fn ignores_leading_whitespace() {
+        assert_eq!(
+            Command::parse(" \n\t|n| a note "),
+            Some((Command::Note, "a note"))
+        );
+    }
+
+    #[test]
+    fn tells_continue_from_cancel() {
+        assert_eq!(Command::parse("|c|"), Some((Command::Continue, "")));
+        assert_eq!(Command::parse("|cancel|"), Some((Command::Cancel, "")));
+    }
+
+    #[test]
+    fn rejects_empty_and_bare_pipe() {
+        assert_eq!(Command::parse(""), None);
+        assert_eq!(Command::parse("|"), None);
+        assert_eq!(Command::parse("   "), None);
+    }
+
+    #[test]
+    fn markers_are_not_bot_commands() {
+        assert!(!Command::Barrier.is_bot_command());
+        assert!(!Command::Dm.is_bot_command());
+        assert!(Command::Tldr.is_bot_command());
+    }
+}
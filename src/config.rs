@@ -0,0 +1,460 @@
+use std::env;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use chrono::NaiveTime;
+use chrono_tz::Tz;
+use log::warn;
+use serde::Deserialize;
+
+/// The settings, loaded on first use
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Settings read at startup. They are loaded from the TOML file in `CONFIG_FILE`, if
+/// any, and every setting can be overridden by the environment variable named like
+/// its key in uppercase.
+// The settings stay flat, so that every key maps to a single environment variable
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// The token of the Discord bot
+    pub discord_token: String,
+    /// The key for the OpenAI api
+    pub openai_key: String,
+    /// The base url of the api, if not the OpenAI one
+    pub openai_base_url: Option<String>,
+    /// The OpenAI organization requests are billed to
+    pub openai_organization: Option<String>,
+    /// The OpenAI project requests are billed to
+    pub openai_project: Option<String>,
+    /// The api endpoint used for completions: `chat`, `responses` or `completions`
+    pub openai_api: String,
+    /// How many times failed requests are retried
+    pub openai_retries: u32,
+    /// The default model
+    pub openai_model: String,
+    /// Models tried in order when the main one is unavailable
+    pub model_fallbacks: Vec<String>,
+    /// The sampling temperature, if different from the api default
+    pub openai_temperature: Option<f32>,
+    /// How requests hint the provider to cache their prompt: `off`, `key` or
+    /// `marker`. Markers need `stable_prompt`, which keeps the prompt at the start.
+    pub prompt_cache_hints: String,
+    /// Whether the prompt starts every request, rather than going near the end of
+    /// the conversation
+    pub stable_prompt: bool,
+    /// How the context is limited: `tokens`, only by the token limit, or `count`,
+    /// also to the last `context_messages` messages
+    pub context_mode: String,
+    /// The maximum number of messages in the context in `count` mode
+    pub context_messages: usize,
+    /// How many alternatives to log with the log probability of every generated
+    /// token, if log probabilities are wanted
+    pub logprobs: Option<u8>,
+    /// How answers are checked before they are posted: `off`, `wordlist` or
+    /// `moderation`
+    pub output_filter: String,
+    /// The words and phrases that block an answer with the `wordlist` output filter
+    pub output_filter_words: Vec<String>,
+    /// The message posted instead of a blocked answer
+    pub output_filter_message: String,
+    /// The file with the default prompt
+    pub prompt_file: String,
+    /// The name of the channels the bot answers in
+    pub channel_name: String,
+    /// Whether messages from other bots are ignored, to avoid answering each other
+    /// in loops
+    pub ignore_bots: bool,
+    /// Ids of the bots whose messages are answered even when `ignore_bots` is set
+    pub allowed_bots: Vec<u64>,
+    /// The maximum number of completions running at once
+    pub max_concurrent_completions: usize,
+    /// Whether a completion is cancelled when a newer message arrives in its
+    /// channel, so that only the latest message is answered. Answers aren't
+    /// streamed, so a cancelled completion never leaves a partial message behind.
+    pub cancel_superseded: bool,
+    /// Maximum number of idle connections to the api kept open. A few are enough,
+    /// since completions are limited by `max_concurrent_completions` anyway.
+    pub http_pool_max_idle_per_host: usize,
+    /// How long idle connections to the api are kept open
+    pub http_pool_idle_timeout_seconds: u64,
+    /// How long a request to the api may take. Long completions can take minutes.
+    pub http_timeout_seconds: u64,
+    /// Maximum number of requests to the api in flight at once, or zero for no
+    /// limit. It also bounds requests that aren't chat completions, such as
+    /// attachment summaries.
+    pub http_max_requests: usize,
+    /// The number of shards, or `None` to use the number recommended by Discord
+    pub shard_count: Option<u64>,
+    /// Whether responses are cached
+    pub enable_cache: bool,
+    /// How long cached responses are kept
+    pub cache_ttl_seconds: u64,
+    /// The maximum number of cached responses
+    pub cache_max_entries: usize,
+    /// Whether responses sampled with a temperature above zero are cached too
+    pub cache_nondeterministic: bool,
+    /// Whether the exact bodies of api requests and responses are logged
+    pub log_openai_raw: bool,
+    /// Whether message contents are logged in full, rather than as length and hash
+    pub log_full_content: bool,
+    /// Secret key of the hashed user ids sent to the api. Required.
+    pub user_id_salt: String,
+    /// Ids of the users that may run admin commands. When empty, nobody can.
+    pub admin_ids: Vec<u64>,
+    /// The models that can be selected with `|model|`
+    pub allowed_models: Vec<String>,
+    /// The hours the bot answers in, such as `09:00-23:00`, or always if unset
+    pub enabled_hours: Option<String>,
+    /// The timezone of `enabled_hours`
+    pub enabled_timezone: String,
+    /// The hours the bot answers in, parsed from `enabled_hours` and
+    /// `enabled_timezone` when loading
+    #[serde(skip)]
+    pub schedule: Option<Schedule>,
+    /// A prefix messages must start with to be answered, if any
+    pub wake_prefix: Option<String>,
+    /// The emoji reacted to barriers, as unicode, `<:name:id>`, `name:id` or an id
+    pub barrier_emoji: Option<String>,
+    /// The emoji reacted to asides, in the same formats as `barrier_emoji`
+    pub aside_emoji: Option<String>,
+    /// Instructions added after the prompt, if any
+    pub system_suffix: Option<String>,
+    /// Whether user content is wrapped in delimiters the model is told to distrust
+    pub injection_guard: bool,
+    /// The delimiter that opens untrusted content
+    pub untrusted_open: String,
+    /// The delimiter that closes untrusted content
+    pub untrusted_close: String,
+    /// The note telling the model about untrusted content, if not the default one
+    pub injection_guard_notice: Option<String>,
+    /// Whether the model is told how users voted on its latest answers
+    pub reaction_signals: bool,
+    /// Whether the model is asked to answer in the language of the latest message
+    pub detect_language: bool,
+    /// Whether pinned messages are part of the context
+    pub include_pinned: bool,
+    /// The extensions of the attachments that are read. When empty, all are.
+    pub allowed_attachment_extensions: Vec<String>,
+    /// Whether large attachments are replaced by a summary
+    pub summarize_attachments: bool,
+    /// How many tokens an attachment needs to be summarized
+    pub attachment_summary_tokens: usize,
+    /// The delimiter between the reasoning of the model and its answer
+    pub thinking_delimiter: String,
+    /// Whether the reasoning of the model is posted as an aside
+    pub send_thinking: bool,
+    /// Whether echoed "Nickname says:" framing is removed from answers
+    pub strip_says_echo: bool,
+    /// Text added before every answer, with the placeholders of `decorate_response`
+    pub response_prefix: String,
+    /// Text added after every answer, with the same placeholders
+    pub response_suffix: String,
+    /// The maximum number of messages an answer is split into
+    pub max_response_messages: usize,
+    /// What happens to the rest of longer answers: `file` or `truncate`
+    pub response_overflow: String,
+    /// Answers longer than this many characters are sent as a file
+    pub long_response_chars: usize,
+    /// The command that renders mermaid diagrams
+    pub mermaid_cli: String,
+    /// The maximum number of bytes uploaded in a single message
+    pub upload_limit: u64,
+}
+
+impl Default for Config {
+    /// Create the default configuration
+    fn default() -> Config {
+        Config {
+            discord_token: String::new(),
+            openai_key: String::new(),
+            openai_base_url: None,
+            openai_organization: None,
+            openai_project: None,
+            openai_api: "chat".to_string(),
+            openai_retries: 2,
+            openai_model: "gpt-3.5-turbo".to_string(),
+            model_fallbacks: Vec::new(),
+            openai_temperature: None,
+            prompt_cache_hints: "off".to_string(),
+            stable_prompt: false,
+            context_mode: "tokens".to_string(),
+            context_messages: 20,
+            logprobs: None,
+            output_filter: "off".to_string(),
+            output_filter_words: Vec::new(),
+            output_filter_message: "I can't help with that.".to_string(),
+            prompt_file: String::new(),
+            channel_name: "omnitea".to_string(),
+            ignore_bots: false,
+            allowed_bots: Vec::new(),
+            max_concurrent_completions: 4,
+            cancel_superseded: false,
+            http_pool_max_idle_per_host: 4,
+            http_pool_idle_timeout_seconds: 90,
+            http_timeout_seconds: 300,
+            http_max_requests: 8,
+            shard_count: None,
+            enable_cache: false,
+            cache_ttl_seconds: 3600,
+            cache_max_entries: 100,
+            cache_nondeterministic: false,
+            log_openai_raw: false,
+            log_full_content: false,
+            user_id_salt: String::new(),
+            admin_ids: Vec::new(),
+            allowed_models: [
+                "gpt-3.5-turbo",
+                "gpt-4",
+                "gpt-4-turbo",
+                "gpt-4o",
+                "gpt-4o-mini",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+            enabled_hours: None,
+            enabled_timezone: "UTC".to_string(),
+            schedule: None,
+            wake_prefix: None,
+            barrier_emoji: None,
+            aside_emoji: None,
+            system_suffix: None,
+            injection_guard: false,
+            untrusted_open: "<untrusted>".to_string(),
+            untrusted_close: "</untrusted>".to_string(),
+            injection_guard_notice: None,
+            reaction_signals: false,
+            detect_language: false,
+            include_pinned: false,
+            allowed_attachment_extensions: Vec::new(),
+            summarize_attachments: false,
+            attachment_summary_tokens: 1000,
+            thinking_delimiter: "---FINAL---".to_string(),
+            send_thinking: false,
+            strip_says_echo: false,
+            response_prefix: String::new(),
+            response_suffix: String::new(),
+            max_response_messages: 5,
+            response_overflow: "file".to_string(),
+            long_response_chars: 4000,
+            mermaid_cli: "mmdc".to_string(),
+            // The limit of servers without boosts
+            upload_limit: 10 * 1024 * 1024,
+        }
+    }
+}
+
+impl Config {
+    /// Get the settings, loading them the first time
+    pub fn get() -> &'static Config {
+        CONFIG.get_or_init(Config::load)
+    }
+
+    /// Load the configuration from `CONFIG_FILE`, if set, and apply the overrides
+    /// from the environment
+    fn load() -> Config {
+        let mut config = match env::var("CONFIG_FILE") {
+            Ok(path) => {
+                let text = std::fs::read_to_string(&path).unwrap_or_else(|why| {
+                    panic!("Failed to read config file {path}: {why}")
+                });
+                toml::from_str(&text)
+                    .unwrap_or_else(|why| panic!("Invalid config file {path}: {why}"))
+            }
+            Err(_) => Config::default(),
+        };
+
+        config.apply_env();
+        config.schedule = config.parse_schedule();
+        config
+    }
+
+    /// Parse `enabled_hours` and `enabled_timezone`. Invalid hours are ignored, so
+    /// that the bot is always on, and an invalid timezone falls back to UTC.
+    fn parse_schedule(&self) -> Option<Schedule> {
+        let hours = self.enabled_hours.as_ref()?;
+
+        let parse = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").ok();
+        let Some((Some(start), Some(end))) = hours
+            .split_once('-')
+            .map(|(start, end)| (parse(start), parse(end)))
+        else {
+            warn!("Invalid ENABLED_HOURS: {}, ignoring it", hours);
+            return None;
+        };
+
+        let timezone = self.enabled_timezone.parse::<Tz>().unwrap_or_else(|_| {
+            warn!(
+                "Invalid ENABLED_TIMEZONE: {}, using UTC",
+                self.enabled_timezone
+            );
+            Tz::UTC
+        });
+
+        Some(Schedule {
+            start,
+            end,
+            timezone,
+        })
+    }
+
+    /// Override the settings that are set in the environment
+    fn apply_env(&mut self) {
+        override_value(&mut self.discord_token, "DISCORD_TOKEN");
+        override_value(&mut self.openai_key, "OPENAI_KEY");
+        override_option(&mut self.openai_base_url, "OPENAI_BASE_URL");
+        override_option(&mut self.openai_organization, "OPENAI_ORGANIZATION");
+        override_option(&mut self.openai_project, "OPENAI_PROJECT");
+        override_value(&mut self.openai_api, "OPENAI_API");
+        override_value(&mut self.openai_retries, "OPENAI_RETRIES");
+        override_value(&mut self.openai_model, "OPENAI_MODEL");
+        override_list(&mut self.model_fallbacks, "MODEL_FALLBACKS");
+        override_option(&mut self.openai_temperature, "OPENAI_TEMPERATURE");
+        override_value(&mut self.prompt_cache_hints, "PROMPT_CACHE_HINTS");
+        override_flag(&mut self.stable_prompt, "STABLE_PROMPT");
+        override_value(&mut self.context_mode, "CONTEXT_MODE");
+        override_value(&mut self.context_messages, "CONTEXT_MESSAGES");
+        override_option(&mut self.logprobs, "LOGPROBS");
+        override_value(&mut self.output_filter, "OUTPUT_FILTER");
+        override_list(&mut self.output_filter_words, "OUTPUT_FILTER_WORDS");
+        override_value(&mut self.output_filter_message, "OUTPUT_FILTER_MESSAGE");
+        override_value(&mut self.prompt_file, "PROMPT_FILE");
+        override_value(&mut self.channel_name, "CHANNEL_NAME");
+        override_flag(&mut self.ignore_bots, "IGNORE_BOTS");
+        override_list(&mut self.allowed_bots, "ALLOWED_BOTS");
+        override_value(
+            &mut self.max_concurrent_completions,
+            "MAX_CONCURRENT_COMPLETIONS",
+        );
+        override_flag(&mut self.cancel_superseded, "CANCEL_SUPERSEDED");
+        override_value(
+            &mut self.http_pool_max_idle_per_host,
+            "HTTP_POOL_MAX_IDLE_PER_HOST",
+        );
+        override_value(
+            &mut self.http_pool_idle_timeout_seconds,
+            "HTTP_POOL_IDLE_TIMEOUT_SECONDS",
+        );
+        override_value(&mut self.http_timeout_seconds, "HTTP_TIMEOUT_SECONDS");
+        override_value(&mut self.http_max_requests, "HTTP_MAX_REQUESTS");
+        override_option(&mut self.shard_count, "SHARD_COUNT");
+        override_flag(&mut self.enable_cache, "ENABLE_CACHE");
+        override_value(&mut self.cache_ttl_seconds, "CACHE_TTL_SECONDS");
+        override_value(&mut self.cache_max_entries, "CACHE_MAX_ENTRIES");
+        override_flag(&mut self.cache_nondeterministic, "CACHE_NONDETERMINISTIC");
+        override_flag(&mut self.log_openai_raw, "LOG_OPENAI_RAW");
+        override_flag(&mut self.log_full_content, "LOG_FULL_CONTENT");
+        override_value(&mut self.user_id_salt, "USER_ID_SALT");
+        override_list(&mut self.admin_ids, "ADMIN_IDS");
+        override_list(&mut self.allowed_models, "ALLOWED_MODELS");
+        override_option(&mut self.enabled_hours, "ENABLED_HOURS");
+        override_value(&mut self.enabled_timezone, "ENABLED_TIMEZONE");
+        override_option(&mut self.wake_prefix, "WAKE_PREFIX");
+        override_option(&mut self.barrier_emoji, "BARRIER_EMOJI");
+        override_option(&mut self.aside_emoji, "ASIDE_EMOJI");
+        override_option(&mut self.system_suffix, "SYSTEM_SUFFIX");
+        override_flag(&mut self.injection_guard, "INJECTION_GUARD");
+        override_value(&mut self.untrusted_open, "UNTRUSTED_OPEN");
+        override_value(&mut self.untrusted_close, "UNTRUSTED_CLOSE");
+        override_option(&mut self.injection_guard_notice, "INJECTION_GUARD_NOTICE");
+        override_flag(&mut self.reaction_signals, "REACTION_SIGNALS");
+        override_flag(&mut self.detect_language, "DETECT_LANGUAGE");
+        override_flag(&mut self.include_pinned, "INCLUDE_PINNED");
+        override_list(
+            &mut self.allowed_attachment_extensions,
+            "ALLOWED_ATTACHMENT_EXTENSIONS",
+        );
+        override_flag(&mut self.summarize_attachments, "SUMMARIZE_ATTACHMENTS");
+        override_value(
+            &mut self.attachment_summary_tokens,
+            "ATTACHMENT_SUMMARY_TOKENS",
+        );
+        override_value(&mut self.thinking_delimiter, "THINKING_DELIMITER");
+        override_flag(&mut self.send_thinking, "SEND_THINKING");
+        override_flag(&mut self.strip_says_echo, "STRIP_SAYS_ECHO");
+        override_value(&mut self.response_prefix, "RESPONSE_PREFIX");
+        override_value(&mut self.response_suffix, "RESPONSE_SUFFIX");
+        override_value(&mut self.max_response_messages, "MAX_RESPONSE_MESSAGES");
+        override_value(&mut self.response_overflow, "RESPONSE_OVERFLOW");
+        override_value(&mut self.long_response_chars, "LONG_RESPONSE_CHARS");
+        override_value(&mut self.mermaid_cli, "MERMAID_CLI");
+        override_value(&mut self.upload_limit, "UPLOAD_LIMIT");
+    }
+}
+
+impl std::fmt::Debug for Config {
+    /// Format the settings without the secrets, so they can be logged
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secret = |value: &str| if value.is_empty() { "" } else { "[REDACTED]" };
+
+        f.debug_struct("Config")
+            .field("discord_token", &secret(&self.discord_token))
+            .field("openai_key", &secret(&self.openai_key))
+            .field("openai_base_url", &self.openai_base_url)
+            .field("openai_api", &self.openai_api)
+            .field("openai_model", &self.openai_model)
+            .field("model_fallbacks", &self.model_fallbacks)
+            .field("prompt_file", &self.prompt_file)
+            .field("channel_name", &self.channel_name)
+            .field("user_id_salt", &secret(&self.user_id_salt))
+            .finish_non_exhaustive()
+    }
+}
+
+/// The hours the bot answers in, which may wrap around midnight
+#[derive(Debug, Clone, Copy)]
+pub struct Schedule {
+    /// When the bot starts answering
+    pub start: NaiveTime,
+    /// When the bot stops answering
+    pub end: NaiveTime,
+    /// The timezone of the hours
+    pub timezone: Tz,
+}
+
+/// Override a setting with an environment variable, if it is set and valid
+fn override_value<T: FromStr>(value: &mut T, name: &str) {
+    if let Ok(text) = env::var(name) {
+        match text.parse() {
+            Ok(parsed) => *value = parsed,
+            Err(_) => warn!("Invalid {}: {}, ignoring it", name, text),
+        }
+    }
+}
+
+/// Override an optional setting with an environment variable, if it is set and valid
+fn override_option<T: FromStr>(value: &mut Option<T>, name: &str) {
+    if let Ok(text) = env::var(name) {
+        match text.parse() {
+            Ok(parsed) => *value = Some(parsed),
+            Err(_) => warn!("Invalid {}: {}, ignoring it", name, text),
+        }
+    }
+}
+
+/// Override a flag with an environment variable, which is on when it is `1` or `true`
+fn override_flag(value: &mut bool, name: &str) {
+    if let Ok(text) = env::var(name) {
+        *value = text == "1" || text.eq_ignore_ascii_case("true");
+    }
+}
+
+/// Override a list with a comma separated environment variable, skipping invalid
+/// items
+fn override_list<T: FromStr>(value: &mut Vec<T>, name: &str) {
+    if let Ok(text) = env::var(name) {
+        *value = text
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .filter_map(|item| match item.parse() {
+                Ok(parsed) => Some(parsed),
+                Err(_) => {
+                    warn!("Invalid item in {}: {}, ignoring it", name, item);
+                    None
+                }
+            })
+            .collect();
+    }
+}
@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+use crate::openai::CompletionUsage;
+
+/// The kinds of failures reported to a metrics sink
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    /// A completion request failed
+    Completion,
+    /// An answer could not be sent to Discord
+    Send,
+}
+
+/// Receives events from the bot, so that they can be shipped to whatever metrics
+/// backend is in use. Every method does nothing by default, so implementations only
+/// need the events they care about.
+pub trait MetricsSink: Send + Sync {
+    /// Called for every message received in a channel the bot answers in
+    fn on_message(&self) {}
+
+    /// Called when a completion finishes, with the time it took
+    fn on_completion(
+        &self,
+        _model: &str,
+        _usage: &CompletionUsage,
+        _latency: Duration,
+    ) {
+    }
+
+    /// Called when something goes wrong
+    fn on_error(&self, _kind: ErrorKind) {}
+}
+
+/// A metrics sink that discards every event
+pub struct NoopMetrics;
+
+impl MetricsSink for NoopMetrics {}
@@ -1,5 +1,62 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, error, info, warn};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tiktoken_rs::tiktoken::cl100k_base_singleton;
+use tiktoken_rs::{cl100k_base_singleton, o200k_base_singleton, CoreBPE};
+
+/// Base URL of the `OpenAI` api
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// Tokens the api adds around every entry of a chat log
+const TOKENS_PER_ENTRY: usize = 3;
+
+/// Tokens the api adds to prime the assistant's reply
+const TOKENS_PER_REPLY: usize = 3;
+
+/// Maximum number of tokens generated by the legacy completions endpoint
+const COMPLETION_MAX_TOKENS: usize = 500;
+
+/// Context windows of known models, matched by prefix in order, so more specific
+/// names go first
+const CONTEXT_WINDOWS: [(&str, usize); 12] = [
+    ("gpt-3.5-turbo-instruct", 4_096),
+    ("gpt-3.5-turbo", 16_385),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-1106", 128_000),
+    ("gpt-4-0125", 128_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4.1", 1_047_576),
+    ("gpt-4", 8_192),
+    ("o1", 200_000),
+    ("o3", 200_000),
+    ("o4", 200_000),
+];
+
+/// Context window assumed for unknown models, which is the smallest one
+const DEFAULT_CONTEXT_WINDOW: usize = 4_096;
+
+/// Maximum number of characters of a response body included in an error
+const ERROR_SNIPPET_LENGTH: usize = 500;
+
+/// Counter used to tell apart idempotency keys created at the same time
+static IDEMPOTENCY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Create a new idempotency key, unique to this process and request
+fn idempotency_key() -> String {
+    let count = IDEMPOTENCY_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    format!("omnitea-{}-{nanos}-{count}", std::process::id())
+}
 
 /// Roles that can be used in a chat log
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -13,6 +70,9 @@ pub enum ChatRole {
     /// The assistant, used for the assistant's response
     #[serde(rename = "assistant")]
     Assistant,
+    /// A tool, used for the results of tool calls
+    #[serde(rename = "tool")]
+    Tool,
 }
 
 impl ToString for ChatRole {
@@ -22,17 +82,63 @@ impl ToString for ChatRole {
             ChatRole::System => "system".to_string(),
             ChatRole::User => "user".to_string(),
             ChatRole::Assistant => "assistant".to_string(),
+            ChatRole::Tool => "tool".to_string(),
         }
     }
 }
 
+/// The function called by a tool call
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolCallFunction {
+    /// The name of the function
+    pub name: String,
+    /// The arguments of the call, encoded as JSON
+    pub arguments: String,
+}
+
+/// A tool call requested by the assistant
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolCall {
+    /// The id of the call, referenced by the tool entry holding its result
+    pub id: String,
+    /// The type of the tool, which is always "function" for now
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The function that is called
+    pub function: ToolCallFunction,
+}
+
 /// A single entry in a chat log
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ChatEntry {
     /// The role of the entry
     pub role: ChatRole,
-    /// The text of the entry
+    /// The text of the entry, which is empty when the assistant only calls tools
+    #[serde(default, deserialize_with = "null_as_empty")]
     pub content: String,
+    /// The id of the tool call this entry is the result of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// The tool calls requested by the assistant in this entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+}
+
+/// Deserialize a string that may be null, treating null as empty
+fn null_as_empty<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Get the number of tokens a model can take in a request, counting the prompt and
+/// the completion together
+pub fn context_window(model: &str) -> usize {
+    CONTEXT_WINDOWS
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map_or(DEFAULT_CONTEXT_WINDOW, |(_, window)| *window)
 }
 
 /// A chat completion request
@@ -42,42 +148,598 @@ struct ChatCompletionRequest {
     model: String,
     /// The chat log
     messages: ChatLog,
+    /// The sampling temperature, if different from the api default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    /// The maximum number of tokens to generate, if limited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<usize>,
+    /// Whether the log probabilities of the generated tokens are returned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<bool>,
+    /// How many of the most likely alternatives are returned for every token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_logprobs: Option<u8>,
+    /// An opaque identifier of the end user, for abuse monitoring
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    /// A key shared by requests with the same prefix, so that they hit the same cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_cache_key: Option<String>,
+}
+
+/// A single input message of a responses request
+#[derive(Serialize, Debug)]
+struct ResponsesInput {
+    /// The role of the message
+    role: String,
+    /// The text of the message
+    content: String,
+}
+
+/// A request to the responses endpoint
+#[derive(Serialize, Debug)]
+struct ResponsesRequest {
+    /// The model used for the response
+    model: String,
+    /// The messages of the conversation
+    input: Vec<ResponsesInput>,
+    /// The sampling temperature, if different from the api default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    /// The maximum number of tokens to generate, if limited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<usize>,
+    /// An opaque identifier of the end user, for abuse monitoring
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    /// A key shared by requests with the same prefix, so that they hit the same cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_cache_key: Option<String>,
+}
+
+impl ResponsesRequest {
+    /// Create a new responses request from a chat log
+    fn new(
+        messages: &ChatLog,
+        options: &CompletionOptions,
+        hints: CacheHints,
+    ) -> ResponsesRequest {
+        let input = messages
+            .0
+            .iter()
+            .map(|entry| ResponsesInput {
+                // Tool results have no role of their own in this endpoint
+                role: match entry.role {
+                    ChatRole::Tool => ChatRole::User.to_string(),
+                    _ => entry.role.to_string(),
+                },
+                content: entry.content.clone(),
+            })
+            .collect();
+
+        ResponsesRequest {
+            model: options.model.clone(),
+            input,
+            temperature: options.temperature,
+            max_output_tokens: options.max_tokens,
+            user: options.user.clone(),
+            prompt_cache_key: hints.key(options),
+        }
+    }
+}
+
+/// A piece of content of a responses output item
+#[derive(Deserialize, Debug)]
+struct ResponsesContent {
+    /// The type of the content, such as "output_text"
+    #[serde(rename = "type")]
+    kind: String,
+    /// The text of the content
+    #[serde(default)]
+    text: String,
+}
+
+/// An output item of a response
+#[derive(Deserialize, Debug)]
+struct ResponsesOutput {
+    /// The type of the item, such as "message"
+    #[serde(rename = "type")]
+    kind: String,
+    /// The content of the item
+    #[serde(default)]
+    content: Vec<ResponsesContent>,
+}
+
+/// The usage information of a response
+#[derive(Deserialize, Debug)]
+struct ResponsesUsage {
+    /// The tokens in the input
+    input_tokens: usize,
+    /// The tokens in the output
+    output_tokens: usize,
+    /// The tokens in the total
+    total_tokens: usize,
+}
+
+/// A response from the responses endpoint
+#[derive(Deserialize, Debug)]
+struct ResponsesResponse {
+    /// The response id
+    id: String,
+    /// The response creation time
+    created_at: usize,
+    /// The output items
+    output: Vec<ResponsesOutput>,
+    /// The response usage
+    usage: ResponsesUsage,
+}
+
+impl From<ResponsesResponse> for ChatCompletionResponse {
+    /// Convert a response into a chat completion response
+    fn from(response: ResponsesResponse) -> ChatCompletionResponse {
+        let content = response
+            .output
+            .iter()
+            .filter(|item| item.kind == "message")
+            .flat_map(|item| &item.content)
+            .filter(|content| content.kind == "output_text")
+            .map(|content| content.text.as_str())
+            .collect::<String>();
+
+        ChatCompletionResponse {
+            id: response.id,
+            object: "response".to_string(),
+            created: response.created_at,
+            choices: vec![ChatCompletionChoice {
+                index: 0,
+                message: ChatEntry::new(ChatRole::Assistant, &content),
+                logprobs: None,
+            }],
+            usage: CompletionUsage {
+                prompt_tokens: response.usage.input_tokens,
+                completion_tokens: response.usage.output_tokens,
+                total_tokens: response.usage.total_tokens,
+            },
+            cached: false,
+        }
+    }
+}
+
+/// A request to the legacy completions endpoint
+#[derive(Serialize, Debug)]
+struct CompletionRequest {
+    /// The model used for the completion
+    model: String,
+    /// The prompt, which is the chat log written out as a transcript
+    prompt: String,
+    /// The maximum number of tokens to generate
+    max_tokens: usize,
+    /// The sampling temperature, if different from the api default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    /// An opaque identifier of the end user, for abuse monitoring
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+}
+
+impl CompletionRequest {
+    /// Create a new completion request from a chat log
+    fn new(messages: &ChatLog, options: &CompletionOptions) -> CompletionRequest {
+        let mut prompt = String::new();
+        for entry in &messages.0 {
+            prompt.push_str(&format!(
+                "{}: {}\n\n",
+                entry.role.to_string(),
+                entry.content
+            ));
+        }
+        prompt.push_str("assistant:");
+
+        CompletionRequest {
+            model: options.model.clone(),
+            prompt,
+            // The endpoint defaults to 16 tokens, which is far too short
+            max_tokens: options.max_tokens.unwrap_or(COMPLETION_MAX_TOKENS),
+            temperature: options.temperature,
+            user: options.user.clone(),
+        }
+    }
+}
+
+/// A choice of the legacy completions endpoint
+#[derive(Deserialize, Debug)]
+struct CompletionChoice {
+    /// The index of the choice
+    index: usize,
+    /// The generated text
+    text: String,
+}
+
+/// A response from the legacy completions endpoint
+#[derive(Deserialize, Debug)]
+struct CompletionResponse {
+    /// The completion id
+    id: String,
+    /// The completion object
+    object: String,
+    /// The completion creation time
+    created: usize,
+    /// The completion choices
+    choices: Vec<CompletionChoice>,
+    /// The completion usage
+    usage: CompletionUsage,
+}
+
+impl From<CompletionResponse> for ChatCompletionResponse {
+    /// Convert a legacy completion response into a chat completion response
+    fn from(response: CompletionResponse) -> ChatCompletionResponse {
+        ChatCompletionResponse {
+            id: response.id,
+            object: response.object,
+            created: response.created,
+            choices: response
+                .choices
+                .into_iter()
+                .map(|choice| ChatCompletionChoice {
+                    index: choice.index,
+                    message: ChatEntry::new(ChatRole::Assistant, choice.text.trim()),
+                    logprobs: None,
+                })
+                .collect(),
+            usage: response.usage,
+            cached: false,
+        }
+    }
+}
+
+/// The api endpoints that can be used to complete a chat
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiEndpoint {
+    /// The chat completions endpoint, `/v1/chat/completions`
+    ChatCompletions,
+    /// The responses endpoint, `/v1/responses`
+    Responses,
+    /// The legacy completions endpoint for instruct models, `/v1/completions`
+    Completions,
+}
+
+impl ApiEndpoint {
+    /// Get an endpoint by name, which is one of "chat", "responses" or "completions"
+    pub fn from_name(name: &str) -> Option<ApiEndpoint> {
+        match name {
+            "chat" => Some(ApiEndpoint::ChatCompletions),
+            "responses" => Some(ApiEndpoint::Responses),
+            "completions" => Some(ApiEndpoint::Completions),
+            _ => None,
+        }
+    }
+}
+
+/// How requests hint the provider to cache the prefix they share with previous
+/// requests, such as a long system prompt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheHints {
+    /// No hints. Providers that cache prefixes on their own still do.
+    Off,
+    /// A `prompt_cache_key`, which OpenAI uses to route requests with the same
+    /// prefix to the same cache
+    Key,
+    /// A `cache_control` marker on the system prompt, which Anthropic compatible
+    /// servers need to cache anything. Only the chat completions endpoint has it.
+    Marker,
+}
+
+impl CacheHints {
+    /// Get the hints by name, which is one of "off", "key" or "marker"
+    pub fn from_name(name: &str) -> Option<CacheHints> {
+        match name {
+            "off" => Some(CacheHints::Off),
+            "key" => Some(CacheHints::Key),
+            "marker" => Some(CacheHints::Marker),
+            _ => None,
+        }
+    }
+
+    /// Get the cache key sent with a request, if any
+    fn key(self, options: &CompletionOptions) -> Option<String> {
+        match self {
+            CacheHints::Key => options.cache_key.clone(),
+            _ => None,
+        }
+    }
+}
+
+/// A request to the moderations endpoint
+#[derive(Serialize, Debug)]
+struct ModerationRequest<'a> {
+    /// The text to check
+    input: &'a str,
+}
+
+/// The result of checking a text with the moderations endpoint
+#[derive(Deserialize, Debug)]
+struct ModerationResult {
+    /// Whether the text breaks the usage policies
+    flagged: bool,
+}
+
+/// A response from the moderations endpoint
+#[derive(Deserialize, Debug)]
+struct ModerationResponse {
+    /// The results, one for each input
+    results: Vec<ModerationResult>,
+}
+
+/// Tokenizer encodings used by the supported models
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// Used by gpt-3.5 and gpt-4
+    Cl100k,
+    /// Used by the gpt-4o family and the o-series models
+    O200k,
+}
+
+impl Encoding {
+    /// Select the encoding used by a model
+    pub fn for_model(model: &str) -> Encoding {
+        let o200k_prefixes = ["gpt-4o", "gpt-4.1", "gpt-5", "o1", "o3", "o4"];
+        if o200k_prefixes
+            .iter()
+            .any(|prefix| model.starts_with(prefix))
+        {
+            Encoding::O200k
+        } else {
+            Encoding::Cl100k
+        }
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Number of times the current thread locked a tokenizer, checked by tests
+    static TOKENIZER_LOCKS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// A tokenizer that is locked while it is in use, so that a whole chat log is
+/// counted under a single lock. Other threads wait for the lock meanwhile.
+pub struct Tokenizer<'a>(&'a CoreBPE);
+
+impl Tokenizer<'_> {
+    /// Lock the tokenizer used by a model, and run a function with it
+    pub fn with<R>(model: &str, f: impl FnOnce(&Tokenizer) -> R) -> R {
+        #[cfg(test)]
+        TOKENIZER_LOCKS.with(|locks| locks.set(locks.get() + 1));
+
+        match Encoding::for_model(model) {
+            Encoding::Cl100k => f(&Tokenizer(&cl100k_base_singleton().lock())),
+            Encoding::O200k => f(&Tokenizer(&o200k_base_singleton().lock())),
+        }
+    }
+
+    /// Count the number of tokens in a text
+    pub fn count(&self, text: &str) -> usize {
+        self.0.encode_ordinary(text).len()
+    }
+}
+
+/// Summarize a text by its length and a short hash, so that it can be logged
+/// without leaking its content while still telling texts apart
+pub fn redact_content(label: &str, text: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    format!(
+        "[{label}: {} chars, hash={:08x}]",
+        text.chars().count(),
+        hasher.finish() & 0xffff_ffff
+    )
 }
 
 impl ChatEntry {
-    /// Count the number of tokens in the entry
-    fn count_tokens(&self) -> usize {
-        let tokenizer = cl100k_base_singleton();
-        let tokenizer = tokenizer.lock();
+    /// Create a new entry with some text
+    fn new(role: ChatRole, content: &str) -> ChatEntry {
+        ChatEntry {
+            role,
+            content: content.to_string(),
+            tool_call_id: None,
+            tool_calls: None,
+        }
+    }
+
+    /// Get a copy of the entry with its content and tool call arguments replaced
+    /// by their length and hash, for logging
+    pub fn redacted(&self) -> ChatEntry {
+        let role = self.role.to_string();
+        ChatEntry {
+            role: self.role.clone(),
+            content: redact_content(&role, &self.content),
+            tool_call_id: self.tool_call_id.clone(),
+            tool_calls: self.tool_calls.as_ref().map(|calls| {
+                calls
+                    .iter()
+                    .map(|call| ToolCall {
+                        function: ToolCallFunction {
+                            name: call.function.name.clone(),
+                            arguments: redact_content(
+                                "arguments",
+                                &call.function.arguments,
+                            ),
+                        },
+                        ..call.clone()
+                    })
+                    .collect()
+            }),
+        }
+    }
 
-        let role_tokens = tokenizer.encode_ordinary(self.role.to_string().as_str());
-        let content_tokens = tokenizer.encode_ordinary(self.content.as_str());
+    /// Count the number of tokens in the entry with the tokenizer of a model.
+    ///
+    /// This is an approximation of how the api counts: every entry costs its role,
+    /// its content and a fixed overhead. Tool calls are counted by their JSON
+    /// encoding, which slightly overestimates them, so that the budget errs on the
+    /// safe side. Entries never hold images, since image inputs aren't sent, so
+    /// there are no image tokens to approximate.
+    fn count_tokens(&self, tokenizer: &Tokenizer) -> usize {
+        let role_tokens = tokenizer.count(self.role.to_string().as_str());
+        let content_tokens = tokenizer.count(self.content.as_str());
+
+        let tool_call_id_tokens = self
+            .tool_call_id
+            .as_ref()
+            .map_or(0, |id| tokenizer.count(id));
+
+        let tool_calls_tokens = self.tool_calls.as_ref().map_or(0, |calls| {
+            calls
+                .iter()
+                .map(|call| {
+                    let encoded = serde_json::to_string(call).unwrap_or_default();
+                    tokenizer.count(&encoded)
+                })
+                .sum()
+        });
 
-        role_tokens.len() + content_tokens.len() + 3
+        role_tokens
+            + content_tokens
+            + tool_call_id_tokens
+            + tool_calls_tokens
+            + TOKENS_PER_ENTRY
     }
 }
 
 impl ChatCompletionRequest {
     /// Create a new chat completion request
-    fn new(model: &str, messages: ChatLog) -> ChatCompletionRequest {
+    fn new(
+        messages: ChatLog,
+        options: &CompletionOptions,
+        hints: CacheHints,
+    ) -> ChatCompletionRequest {
         ChatCompletionRequest {
-            model: model.to_string(),
+            model: options.model.clone(),
             messages,
+            temperature: options.temperature,
+            max_tokens: options.max_tokens,
+            logprobs: options.logprobs.map(|_| true),
+            top_logprobs: options.logprobs,
+            user: options.user.clone(),
+            prompt_cache_key: hints.key(options),
         }
     }
+
+    /// Serialize the request, marking the leading system message as cacheable if
+    /// the provider needs explicit markers. Only a message at the very start is
+    /// marked, since a cached prefix has to begin with it. Marked content is sent
+    /// as a list of blocks, since that is the only place a marker fits.
+    fn to_body(&self, hints: CacheHints) -> serde_json::Result<String> {
+        let mut body = serde_json::to_value(self)?;
+
+        if hints == CacheHints::Marker {
+            let system = body["messages"]
+                .as_array_mut()
+                .and_then(|messages| messages.first_mut())
+                .filter(|message| message["role"] == "system");
+            if let Some(system) = system {
+                let text = system["content"].take();
+                system["content"] = serde_json::json!([{
+                    "type": "text",
+                    "text": text,
+                    "cache_control": { "type": "ephemeral" },
+                }]);
+            }
+        }
+
+        serde_json::to_string(&body)
+    }
 }
 
-impl From<ChatLog> for ChatCompletionRequest {
-    /// Create a new chat completion request from a chat log
-    fn from(log: ChatLog) -> ChatCompletionRequest {
-        // Get model name from environment variable
-        let model = std::env::var("OPENAI_MODEL")
-            .unwrap_or_else(|_| "gpt-3.5-turbo".to_string());
-        ChatCompletionRequest::new(&model, log)
-        //ChatCompletionRequest::new("gpt-4", log)
+/// Options that control how a chat log is completed
+#[derive(Debug, Clone)]
+pub struct CompletionOptions {
+    /// The model used for the completion
+    pub model: String,
+    /// Models tried in order when the main one is unavailable or rate limited
+    pub fallbacks: Vec<String>,
+    /// The sampling temperature, if different from the api default
+    pub temperature: Option<f32>,
+    /// The maximum number of tokens to generate, if limited
+    pub max_tokens: Option<usize>,
+    /// How many alternatives to return with the log probability of every token, if
+    /// log probabilities are wanted. Only the chat completions endpoint supports it.
+    pub logprobs: Option<u8>,
+    /// An opaque identifier of the end user, which helps the api detect abuse
+    pub user: Option<String>,
+    /// A key shared by requests that start with the same prompt, used as a cache
+    /// hint if the provider supports it
+    pub cache_key: Option<String>,
+}
+
+/// An error while completing a chat
+#[derive(Debug)]
+pub enum CompletionError {
+    /// The request could not be sent, or its response could not be read
+    Request(String),
+    /// The api answered with an error status, and the start of the body
+    Status(StatusCode, String),
+    /// The response didn't have the expected shape
+    Parse(String),
+    /// The response had no choices
+    NoChoices,
+}
+
+impl CompletionError {
+    /// Check whether the same request may succeed if it is sent again
+    fn is_retryable(&self) -> bool {
+        match self {
+            CompletionError::Request(_) => true,
+            CompletionError::Status(status, _) => {
+                *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+            }
+            _ => false,
+        }
+    }
+
+    /// Check whether the error means the model can't be used right now, so that
+    /// another model should be tried
+    fn is_model_unavailable(&self) -> bool {
+        match self {
+            CompletionError::Status(status, _) => {
+                *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::NOT_FOUND
+                    || status.is_server_error()
+            }
+            _ => false,
+        }
     }
 }
 
+impl std::fmt::Display for CompletionError {
+    /// Describe the error
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompletionError::Request(e) => write!(f, "Request failed: {e}"),
+            CompletionError::Status(status, body) => {
+                write!(f, "Api error ({status}): {body}")
+            }
+            CompletionError::Parse(e) => write!(f, "{e}"),
+            CompletionError::NoChoices => write!(f, "No choices"),
+        }
+    }
+}
+
+/// The result of completing a chat log
+#[derive(Debug)]
+pub struct Completion {
+    /// The message written by the assistant
+    pub message: ChatEntry,
+    /// The usage of the request
+    pub usage: CompletionUsage,
+    /// The model that wrote the message, which may be a fallback
+    pub model: String,
+    /// The log probabilities of the tokens of the message, if requested
+    pub logprobs: Option<ChoiceLogprobs>,
+    /// Whether the completion was answered from the cache, without using any tokens
+    pub cached: bool,
+}
+
 /// A chat log, which is a list of chat entries
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ChatLog(pub Vec<ChatEntry>);
@@ -101,27 +763,59 @@ impl core::fmt::Debug for ChatLog {
 }
 
 /// Chat completion choice
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChatCompletionChoice {
     /// The text of the choice
     pub index: usize,
     /// The message of the choice
     pub message: ChatEntry,
+    /// The log probabilities of the tokens of the message, if requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<ChoiceLogprobs>,
+}
+
+/// The log probabilities of the tokens of a choice
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChoiceLogprobs {
+    /// The tokens of the message, which is missing for refusals
+    #[serde(default)]
+    pub content: Option<Vec<TokenLogprob>>,
+}
+
+/// The log probability of a generated token
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenLogprob {
+    /// The token
+    pub token: String,
+    /// The log probability of the token
+    pub logprob: f64,
+    /// The most likely tokens in this position, if requested
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+/// The log probability of an alternative to a generated token
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopLogprob {
+    /// The token
+    pub token: String,
+    /// The log probability of the token
+    pub logprob: f64,
 }
 
 /// A completion usage information
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CompletionUsage {
     /// The tokens in the prompt
-    prompt_tokens: usize,
+    pub prompt_tokens: usize,
     /// The tokens in the completion
-    completion_tokens: usize,
+    pub completion_tokens: usize,
     /// The tokens in the total
-    total_tokens: usize,
+    pub total_tokens: usize,
 }
 
 /// A chat completion response
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChatCompletionResponse {
     /// The completion id
     id: String,
@@ -133,6 +827,22 @@ pub struct ChatCompletionResponse {
     pub choices: Vec<ChatCompletionChoice>,
     /// The completion usage
     pub usage: CompletionUsage,
+    /// Whether the response was answered from the cache, without using any tokens
+    #[serde(skip)]
+    pub cached: bool,
+}
+
+impl ChatCompletionResponse {
+    /// Get the message of the first choice, which is the only one unless more were
+    /// requested
+    pub fn first_message(&self) -> Option<&ChatEntry> {
+        self.choices.first().map(|choice| &choice.message)
+    }
+
+    /// Get the number of tokens used by the prompt and the completion together
+    pub fn total_tokens(&self) -> usize {
+        self.usage.total_tokens
+    }
 }
 
 /// `OpenAI` api clients
@@ -141,6 +851,167 @@ pub struct OpenAI {
     client: reqwest::Client,
     /// OpenAI api key
     api_key: String,
+    /// Base URL of the api, which can point to a compatible server
+    base_url: String,
+    /// Organization the requests are billed to, if any
+    organization: Option<String>,
+    /// Project the requests are billed to, if any
+    project: Option<String>,
+    /// Cache of previous responses, if enabled
+    cache: Option<ResponseCache>,
+    /// The endpoint used to complete chats
+    endpoint: ApiEndpoint,
+    /// The rate limits reported by the latest response
+    rate_limits: std::sync::Mutex<Option<RateLimits>>,
+    /// How many times a failed request is retried
+    retries: u32,
+    /// Bounds the number of requests in flight, if limited
+    requests: Option<tokio::sync::Semaphore>,
+    /// How requests hint the provider to cache their prefix
+    cache_hints: CacheHints,
+    /// Whether the exact request and response bodies are logged
+    log_raw: bool,
+}
+
+/// Settings for the HTTP connections to the api
+#[derive(Debug, Clone)]
+pub struct PoolSettings {
+    /// Maximum number of idle connections kept open per host
+    pub max_idle_per_host: usize,
+    /// How long an idle connection is kept open before it is closed
+    pub idle_timeout: Duration,
+    /// How long a request may take before it is abandoned
+    pub request_timeout: Duration,
+    /// Maximum number of requests in flight at once, or zero for no limit
+    pub max_requests: usize,
+}
+
+/// Rate limit information sent by the api in the `x-ratelimit-*` headers
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    /// Maximum number of requests in the current window
+    pub limit_requests: Option<u64>,
+    /// Maximum number of tokens in the current window
+    pub limit_tokens: Option<u64>,
+    /// Requests left in the current window
+    pub remaining_requests: Option<u64>,
+    /// Tokens left in the current window
+    pub remaining_tokens: Option<u64>,
+    /// Time until the request limit resets, such as "1s"
+    pub reset_requests: Option<String>,
+    /// Time until the token limit resets, such as "6m0s"
+    pub reset_tokens: Option<String>,
+}
+
+impl RateLimits {
+    /// Read the rate limits from the headers of a response, if it has any
+    fn from_headers(headers: &HeaderMap) -> Option<RateLimits> {
+        let text = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let number =
+            |name: &str| text(name).and_then(|value| value.parse::<u64>().ok());
+
+        let limits = RateLimits {
+            limit_requests: number("x-ratelimit-limit-requests"),
+            limit_tokens: number("x-ratelimit-limit-tokens"),
+            remaining_requests: number("x-ratelimit-remaining-requests"),
+            remaining_tokens: number("x-ratelimit-remaining-tokens"),
+            reset_requests: text("x-ratelimit-reset-requests"),
+            reset_tokens: text("x-ratelimit-reset-tokens"),
+        };
+
+        if limits.remaining_requests.is_none() && limits.remaining_tokens.is_none() {
+            None
+        } else {
+            Some(limits)
+        }
+    }
+
+    /// Check whether less than a tenth of the requests or tokens are left
+    pub fn is_low(&self) -> bool {
+        let low = |remaining: Option<u64>, limit: Option<u64>| match (remaining, limit)
+        {
+            (Some(remaining), Some(limit)) => remaining.saturating_mul(10) < limit,
+            _ => false,
+        };
+
+        low(self.remaining_requests, self.limit_requests)
+            || low(self.remaining_tokens, self.limit_tokens)
+    }
+}
+
+/// A cache of completion responses, keyed by a hash of the request body
+pub struct ResponseCache {
+    /// How long a response stays valid
+    ttl: Duration,
+    /// Maximum number of responses kept
+    max_entries: usize,
+    /// Whether requests sampled with a temperature above zero are cached too
+    cache_nondeterministic: bool,
+    /// The cached responses and when they were stored
+    entries: std::sync::Mutex<HashMap<u64, (Instant, ChatCompletionResponse)>>,
+}
+
+impl ResponseCache {
+    /// Create a new, empty response cache
+    pub fn new(
+        ttl: Duration,
+        max_entries: usize,
+        cache_nondeterministic: bool,
+    ) -> ResponseCache {
+        ResponseCache {
+            ttl,
+            max_entries,
+            cache_nondeterministic,
+            entries: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check whether requests made with these options can be cached. When no
+    /// temperature is given the api samples with a temperature of one.
+    fn accepts(&self, options: &CompletionOptions) -> bool {
+        self.cache_nondeterministic || options.temperature.is_some_and(|t| t <= 0.0)
+    }
+
+    /// Get the key of a request body
+    fn key(body: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Get a response that is still valid from the cache
+    fn get(&self, key: u64) -> Option<ChatCompletionResponse> {
+        let mut entries = self.entries.lock().ok()?;
+        entries.retain(|_, (stored, _)| stored.elapsed() < self.ttl);
+        entries.get(&key).map(|(_, response)| response.clone())
+    }
+
+    /// Store a response, evicting the oldest one if the cache is full
+    fn insert(&self, key: u64, response: ChatCompletionResponse) {
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+
+        entries.retain(|_, (stored, _)| stored.elapsed() < self.ttl);
+        if entries.len() >= self.max_entries {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (stored, _))| *stored)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        if self.max_entries > 0 {
+            entries.insert(key, (Instant::now(), response));
+        }
+    }
 }
 
 impl ChatLog {
@@ -151,10 +1022,7 @@ impl ChatLog {
 
     /// Add a new entry to the chat log
     pub fn add(mut self, role: ChatRole, content: &str) -> ChatLog {
-        self.0.push(ChatEntry {
-            role,
-            content: content.to_string(),
-        });
+        self.0.push(ChatEntry::new(role, content));
         self
     }
 
@@ -173,28 +1041,141 @@ impl ChatLog {
         self.add(ChatRole::Assistant, content)
     }
 
-    /// Complete the chat log
-    pub async fn complete(self, client: &OpenAI) -> Result<ChatEntry, String> {
-        client.complete_chat(self).await.map_or_else(
-            |e| Err(e.to_string()),
-            |response| {
-                response.choices.get(0).map_or_else(
-                    || Err("No choices".to_string()),
-                    |choice| Ok(choice.message.clone()),
-                )
-            },
-        )
+    /// Add a new tool entry to the chat log, holding the result of a tool call.
+    /// Nothing runs tools yet, this is the building block for it.
+    #[allow(dead_code)]
+    pub fn tool(mut self, call_id: &str, content: &str) -> ChatLog {
+        self.0.push(ChatEntry {
+            role: ChatRole::Tool,
+            content: content.to_string(),
+            tool_call_id: Some(call_id.to_string()),
+            tool_calls: None,
+        });
+        self
     }
 
-    /// Count the number of tokens in the chat log
-    pub fn count_tokens(&self) -> usize {
-        self.0.iter().map(ChatEntry::count_tokens).sum()
+    /// Complete the chat log. If the model is unavailable or rate limited, the
+    /// fallback models are tried in order, each with its own retries.
+    pub async fn complete(
+        self,
+        client: &OpenAI,
+        options: &CompletionOptions,
+    ) -> Result<Completion, CompletionError> {
+        let fallbacks = options
+            .fallbacks
+            .iter()
+            .filter(|model| **model != options.model);
+
+        let mut last_error = CompletionError::NoChoices;
+        for model in std::iter::once(&options.model).chain(fallbacks) {
+            let model_options = CompletionOptions {
+                model: model.clone(),
+                ..options.clone()
+            };
+
+            match client.complete_chat_with_retry(&self, &model_options).await {
+                Ok(response) => {
+                    if *model != options.model {
+                        info!("Completed with fallback model {}", model);
+                    }
+
+                    let choice = response
+                        .choices
+                        .into_iter()
+                        .next()
+                        .ok_or(CompletionError::NoChoices)?;
+
+                    return Ok(Completion {
+                        message: choice.message,
+                        usage: response.usage,
+                        model: model.clone(),
+                        logprobs: choice.logprobs,
+                        cached: response.cached,
+                    });
+                }
+                Err(e) if e.is_model_unavailable() => {
+                    warn!("Model {} is unavailable: {}", model, e);
+                    last_error = e;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Count the number of tokens in the chat log, as seen by the given model,
+    /// including the tokens that prime the assistant's reply
+    pub fn count_tokens(&self, model: &str) -> usize {
+        // The tokenizer is locked once for the whole log
+        Tokenizer::with(model, |tokenizer| {
+            self.0
+                .iter()
+                .map(|entry| entry.count_tokens(tokenizer))
+                .sum::<usize>()
+        }) + TOKENS_PER_REPLY
     }
 
     /// Pop the last entry from the chat log, removing it
     pub fn pop(&mut self) -> Option<ChatEntry> {
         self.0.pop()
     }
+
+    /// Append the entries of another chat log after the entries of this one
+    pub fn concat(mut self, other: ChatLog) -> ChatLog {
+        self.extend(other.0);
+        self
+    }
+
+    /// Get a copy of the chat log with the content of every entry redacted, for
+    /// logging
+    pub fn redacted(&self) -> ChatLog {
+        ChatLog(self.0.iter().map(ChatEntry::redacted).collect())
+    }
+
+    /// Get a copy of the chat log without its system entries
+    pub fn without_system(&self) -> ChatLog {
+        ChatLog(
+            self.0
+                .iter()
+                .filter(|entry| entry.role != ChatRole::System)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Get a chat log with only the system entries of this one
+    pub fn system_messages(&self) -> ChatLog {
+        ChatLog(
+            self.0
+                .iter()
+                .filter(|entry| entry.role == ChatRole::System)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Get the most recent entry with the given role
+    pub fn last_of(&self, role: ChatRole) -> Option<&ChatEntry> {
+        self.0.iter().rev().find(|entry| entry.role == role)
+    }
+
+    /// Get the most recent user entry
+    pub fn last_user_message(&self) -> Option<&ChatEntry> {
+        self.last_of(ChatRole::User)
+    }
+
+    /// Get the most recent assistant entry
+    pub fn last_assistant_message(&self) -> Option<&ChatEntry> {
+        self.last_of(ChatRole::Assistant)
+    }
+}
+
+impl Extend<ChatEntry> for ChatLog {
+    /// Add entries to the end of the chat log, preserving their order
+    fn extend<T: IntoIterator<Item = ChatEntry>>(&mut self, iter: T) {
+        self.0.extend(iter);
+    }
 }
 
 impl OpenAI {
@@ -203,24 +1184,795 @@ impl OpenAI {
         OpenAI {
             client: reqwest::Client::new(),
             api_key,
+            base_url: DEFAULT_BASE_URL.to_string(),
+            organization: None,
+            project: None,
+            cache: None,
+            endpoint: ApiEndpoint::ChatCompletions,
+            rate_limits: std::sync::Mutex::new(None),
+            retries: 0,
+            requests: None,
+            cache_hints: CacheHints::Off,
+            log_raw: false,
         }
     }
 
-    /// Complete a chat
-    pub async fn complete_chat(
+    /// Set how connections to the api are pooled and how many requests can be in
+    /// flight at once
+    pub fn pool(mut self, settings: &PoolSettings) -> OpenAI {
+        match reqwest::Client::builder()
+            .pool_max_idle_per_host(settings.max_idle_per_host)
+            .pool_idle_timeout(settings.idle_timeout)
+            .timeout(settings.request_timeout)
+            .build()
+        {
+            Ok(client) => self.client = client,
+            Err(why) => error!("Error building the HTTP client: {}", why),
+        }
+        self.requests = (settings.max_requests > 0)
+            .then(|| tokio::sync::Semaphore::new(settings.max_requests));
+        self
+    }
+
+    /// Set how many times a failed request is retried
+    pub fn retries(mut self, retries: u32) -> OpenAI {
+        self.retries = retries;
+        self
+    }
+
+    /// Get the rate limits reported by the latest response
+    pub fn rate_limits(&self) -> Option<RateLimits> {
+        self.rate_limits
+            .lock()
+            .ok()
+            .and_then(|limits| limits.clone())
+    }
+
+    /// Set the endpoint used to complete chats
+    pub fn endpoint(mut self, endpoint: ApiEndpoint) -> OpenAI {
+        self.endpoint = endpoint;
+        self
+    }
+
+    /// Set how requests hint the provider to cache their prefix
+    pub fn cache_hints(mut self, hints: CacheHints) -> OpenAI {
+        self.cache_hints = hints;
+        self
+    }
+
+    /// Set whether the exact request and response bodies are logged, with the api
+    /// key redacted
+    pub fn log_raw(mut self, log_raw: bool) -> OpenAI {
+        self.log_raw = log_raw;
+        self
+    }
+
+    /// Set the cache used to avoid repeating identical requests
+    pub fn cache(mut self, cache: Option<ResponseCache>) -> OpenAI {
+        self.cache = cache;
+        self
+    }
+
+    /// Set the base URL of the api, falling back to the `OpenAI` one
+    pub fn base_url(mut self, base_url: Option<String>) -> OpenAI {
+        self.base_url = base_url.map_or_else(
+            || DEFAULT_BASE_URL.to_string(),
+            |url| url.trim_end_matches('/').to_string(),
+        );
+        self
+    }
+
+    /// Set the organization sent in the `OpenAI-Organization` header
+    pub fn organization(mut self, organization: Option<String>) -> OpenAI {
+        self.organization = organization;
+        self
+    }
+
+    /// Set the project sent in the `OpenAI-Project` header
+    pub fn project(mut self, project: Option<String>) -> OpenAI {
+        self.project = project;
+        self
+    }
+
+    /// Replace the api key in a piece of text, so it can be logged safely
+    fn redact(&self, text: &str) -> String {
+        if self.api_key.is_empty() {
+            text.to_string()
+        } else {
+            text.replace(&self.api_key, "[REDACTED]")
+        }
+    }
+
+    /// Get the start of a response body, which usually holds the error message
+    fn snippet(&self, text: &str) -> String {
+        let body = self.redact(text);
+        let mut snippet = body.chars().take(ERROR_SNIPPET_LENGTH).collect::<String>();
+        if body.chars().count() > ERROR_SNIPPET_LENGTH {
+            snippet.push_str("...");
+        }
+        snippet
+    }
+
+    /// Post a request body to an endpoint of the api, returning the status and the
+    /// body of the response. When raw logging is on, the exact request and response
+    /// bodies are logged.
+    async fn post(
         &self,
-        chat: ChatLog,
-    ) -> Result<ChatCompletionResponse, reqwest::Error> {
-        let request = ChatCompletionRequest::from(chat);
+        path: &str,
+        body: String,
+        idempotency_key: &str,
+    ) -> Result<(StatusCode, String), CompletionError> {
+        let url = format!("{}/{path}", self.base_url);
+
+        // Wait for a free slot if the requests in flight are limited
+        let _permit = match &self.requests {
+            Some(requests) => Some(
+                requests
+                    .acquire()
+                    .await
+                    .map_err(|e| CompletionError::Request(e.to_string()))?,
+            ),
+            None => None,
+        };
+
+        let log_raw = self.log_raw;
+
+        if log_raw {
+            debug!(
+                "OpenAI request: POST {url} (Authorization: Bearer [REDACTED]) {}",
+                self.redact(&body)
+            );
+        }
 
         // Make post request to OpenAI
-        self.client
-            .post("https://api.openai.com/v1/chat/completions")
+        let mut builder = self
+            .client
+            .post(&url)
             .bearer_auth(self.api_key.clone())
-            .json(&request)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("Idempotency-Key", idempotency_key);
+
+        if let Some(organization) = &self.organization {
+            builder = builder.header("OpenAI-Organization", organization);
+        }
+        if let Some(project) = &self.project {
+            builder = builder.header("OpenAI-Project", project);
+        }
+
+        let response = builder
+            .body(body)
             .send()
-            .await?
-            .json::<ChatCompletionResponse>()
             .await
+            .map_err(|e| CompletionError::Request(e.to_string()))?;
+
+        let status = response.status();
+
+        // Keep the latest rate limits around, so that callers can pace themselves
+        if let Some(limits) = RateLimits::from_headers(response.headers()) {
+            debug!("Rate limits: {:?}", limits);
+            if let Ok(mut latest) = self.rate_limits.lock() {
+                *latest = Some(limits);
+            }
+        }
+
+        let text = response
+            .text()
+            .await
+            .map_err(|e| CompletionError::Request(e.to_string()))?;
+
+        if log_raw {
+            debug!("OpenAI response ({status}): {}", self.redact(&text));
+        }
+
+        Ok((status, text))
+    }
+
+    /// Parse the body of a response. The body may hold the content of a chat, so
+    /// it is only logged and included in the error when raw logging is on.
+    fn parse<T: DeserializeOwned>(
+        &self,
+        status: StatusCode,
+        text: &str,
+    ) -> Result<T, CompletionError> {
+        serde_json::from_str::<T>(text).map_err(|e| {
+            if self.log_raw {
+                error!(
+                    "Could not parse OpenAI response ({status}): {}",
+                    self.redact(text)
+                );
+                CompletionError::Parse(format!(
+                    "Could not parse response ({status}): {e}. Body: {}",
+                    self.snippet(text)
+                ))
+            } else {
+                error!(
+                    "Could not parse OpenAI response ({status}), {} bytes long",
+                    text.len()
+                );
+                CompletionError::Parse(format!(
+                    "Could not parse response ({status}): {e}"
+                ))
+            }
+        })
+    }
+
+    /// Complete a chat, using the configured api endpoint. Requests sent with the
+    /// same idempotency key are only processed once by the api.
+    pub async fn complete_chat(
+        &self,
+        chat: ChatLog,
+        options: &CompletionOptions,
+        idempotency_key: &str,
+    ) -> Result<ChatCompletionResponse, CompletionError> {
+        let (path, body) = match self.endpoint {
+            ApiEndpoint::ChatCompletions => (
+                "chat/completions",
+                ChatCompletionRequest::new(chat, options, self.cache_hints)
+                    .to_body(self.cache_hints),
+            ),
+            ApiEndpoint::Responses => (
+                "responses",
+                serde_json::to_string(&ResponsesRequest::new(
+                    &chat,
+                    options,
+                    self.cache_hints,
+                )),
+            ),
+            ApiEndpoint::Completions => (
+                "completions",
+                serde_json::to_string(&CompletionRequest::new(&chat, options)),
+            ),
+        };
+        let body = body.map_err(|e| CompletionError::Request(e.to_string()))?;
+
+        // Identical requests are answered from the cache
+        let cache = self.cache.as_ref().filter(|cache| cache.accepts(options));
+        let cache_key = ResponseCache::key(&body);
+        if let Some(response) = cache.and_then(|cache| cache.get(cache_key)) {
+            debug!("Answering from the cache");
+            return Ok(ChatCompletionResponse {
+                cached: true,
+                ..response
+            });
+        }
+
+        let (status, text) = self.post(path, body, idempotency_key).await?;
+
+        if !status.is_success() {
+            return Err(CompletionError::Status(status, self.snippet(&text)));
+        }
+
+        let response = match self.endpoint {
+            ApiEndpoint::ChatCompletions => {
+                self.parse::<ChatCompletionResponse>(status, &text)?
+            }
+            ApiEndpoint::Responses => {
+                self.parse::<ResponsesResponse>(status, &text)?.into()
+            }
+            ApiEndpoint::Completions => {
+                self.parse::<CompletionResponse>(status, &text)?.into()
+            }
+        };
+
+        debug!("Request used {} tokens", response.total_tokens());
+
+        // Responses without a message are never worth repeating
+        if let Some(cache) = cache.filter(|_| response.first_message().is_some()) {
+            cache.insert(cache_key, response.clone());
+        }
+
+        Ok(response)
+    }
+
+    /// Check a text with the moderations endpoint, returning whether it was flagged
+    pub async fn moderate(&self, text: &str) -> Result<bool, CompletionError> {
+        let body = serde_json::to_string(&ModerationRequest { input: text })
+            .map_err(|e| CompletionError::Request(e.to_string()))?;
+
+        let (status, text) = self.post("moderations", body, &idempotency_key()).await?;
+
+        if !status.is_success() {
+            return Err(CompletionError::Status(status, self.snippet(&text)));
+        }
+
+        let response = self.parse::<ModerationResponse>(status, &text)?;
+        Ok(response.results.iter().any(|result| result.flagged))
+    }
+
+    /// Complete a chat, retrying with exponential backoff when the request fails
+    /// in a way that may be temporary. Every attempt uses the same idempotency key,
+    /// so a request that succeeded but whose response was lost isn't billed twice.
+    pub async fn complete_chat_with_retry(
+        &self,
+        chat: &ChatLog,
+        options: &CompletionOptions,
+    ) -> Result<ChatCompletionResponse, CompletionError> {
+        let key = idempotency_key();
+        let mut attempt = 0;
+        loop {
+            match self.complete_chat(chat.clone(), options, &key).await {
+                Err(e) if e.is_retryable() && attempt < self.retries => {
+                    let delay = Duration::from_secs(1 << attempt.min(5));
+                    warn!("Request failed: {}, retrying in {:?}", e, delay);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Options for a test completion
+    fn options() -> CompletionOptions {
+        CompletionOptions {
+            model: "gpt-4o".to_string(),
+            fallbacks: Vec::new(),
+            temperature: None,
+            max_tokens: None,
+            logprobs: None,
+            user: None,
+            cache_key: None,
+        }
+    }
+
+    /// A successful chat completions response with the given answer
+    fn completion_body(content: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": "chatcmpl-test",
+            "object": "chat.completion",
+            "created": 0,
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": content },
+            }],
+            "usage": {
+                "prompt_tokens": 10,
+                "completion_tokens": 2,
+                "total_tokens": 12,
+            },
+        })
+    }
+
+    /// A client that talks to the mock server
+    fn client(server: &MockServer) -> OpenAI {
+        OpenAI::new("test-key".to_string()).base_url(Some(server.uri()))
+    }
+
+    #[test]
+    fn concat_keeps_the_order_of_entries() {
+        let log = ChatLog::new()
+            .system("a")
+            .user("b")
+            .concat(ChatLog::new().assistant("c").user("d"));
+
+        let contents = log
+            .0
+            .iter()
+            .map(|entry| entry.content.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(contents, ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn concat_adds_token_counts() {
+        let first = ChatLog::new().system("You are helpful.").user("Hi there");
+        let second = ChatLog::new().assistant("Hello! How can I help?");
+        let separate = first.count_tokens("gpt-4o") + second.count_tokens("gpt-4o");
+
+        // Each count includes the tokens that prime the reply, which the combined
+        // log only has once
+        assert_eq!(
+            first.concat(second).count_tokens("gpt-4o"),
+            separate - TOKENS_PER_REPLY
+        );
+    }
+
+    #[test]
+    fn splits_system_entries_from_the_rest() {
+        let log = ChatLog::new()
+            .system("a")
+            .user("b")
+            .assistant("c")
+            .system("d");
+
+        let contents = |log: ChatLog| {
+            log.0
+                .into_iter()
+                .map(|entry| entry.content)
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(contents(log.without_system()), ["b", "c"]);
+        assert_eq!(contents(log.system_messages()), ["a", "d"]);
+    }
+
+    #[test]
+    fn finds_the_last_entry_of_a_role() {
+        let log = ChatLog::new()
+            .system("a")
+            .user("b")
+            .assistant("c")
+            .user("d")
+            .system("e");
+
+        let content =
+            |entry: Option<&ChatEntry>| entry.map(|entry| entry.content.clone());
+        assert_eq!(content(log.last_user_message()), Some("d".to_string()));
+        assert_eq!(content(log.last_assistant_message()), Some("c".to_string()));
+        assert_eq!(
+            content(log.last_of(ChatRole::System)),
+            Some("e".to_string())
+        );
+        assert_eq!(content(log.last_of(ChatRole::Tool)), None);
+    }
+
+    #[test]
+    fn selects_the_encoding_of_a_model() {
+        assert_eq!(Encoding::for_model("gpt-3.5-turbo"), Encoding::Cl100k);
+        assert_eq!(Encoding::for_model("gpt-4"), Encoding::Cl100k);
+        assert_eq!(Encoding::for_model("gpt-4-turbo"), Encoding::Cl100k);
+        assert_eq!(Encoding::for_model("gpt-4o"), Encoding::O200k);
+        assert_eq!(Encoding::for_model("gpt-4o-mini"), Encoding::O200k);
+        assert_eq!(Encoding::for_model("o1-mini"), Encoding::O200k);
+        assert_eq!(Encoding::for_model("unknown-model"), Encoding::Cl100k);
+    }
+
+    #[test]
+    fn counts_depend_on_the_model() {
+        // The o200k encoding is much denser than cl100k for non-Latin scripts
+        let chat = ChatLog::new().user("नमस्ते दुनिया, आज आप कैसे हैं? मुझे चाय बहुत पसंद है।");
+
+        assert!(chat.count_tokens("gpt-4o") < chat.count_tokens("gpt-4"));
+    }
+
+    /// An assistant entry calling a tool, and the entry with its result, as the api
+    /// sends them
+    const TOOL_CALL_FIXTURE: &str = r#"[
+        {
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{
+                "id": "call_abc123",
+                "type": "function",
+                "function": {
+                    "name": "get_weather",
+                    "arguments": "{\"city\": \"Paris\"}"
+                }
+            }]
+        },
+        {
+            "role": "tool",
+            "content": "Sunny, 21 degrees",
+            "tool_call_id": "call_abc123"
+        }
+    ]"#;
+
+    #[test]
+    fn counts_a_plain_entry() {
+        // "user" is one token and "Hello world" two, in both encodings
+        let chat = ChatLog::new().user("Hello world");
+
+        for model in ["gpt-4", "gpt-4o"] {
+            assert_eq!(
+                chat.count_tokens(model),
+                1 + 2 + TOKENS_PER_ENTRY + TOKENS_PER_REPLY
+            );
+        }
+    }
+
+    #[test]
+    fn counts_tool_calls_and_their_results() {
+        let entries: Vec<ChatEntry> = serde_json::from_str(TOOL_CALL_FIXTURE).unwrap();
+        let call =
+            serde_json::to_string(&entries[0].tool_calls.as_ref().unwrap()[0]).unwrap();
+
+        Tokenizer::with("gpt-4o", |tokenizer| {
+            assert_eq!(
+                entries[0].count_tokens(tokenizer),
+                tokenizer.count("assistant")
+                    + tokenizer.count(&call)
+                    + TOKENS_PER_ENTRY
+            );
+            assert_eq!(
+                entries[1].count_tokens(tokenizer),
+                tokenizer.count("tool")
+                    + tokenizer.count("Sunny, 21 degrees")
+                    + tokenizer.count("call_abc123")
+                    + TOKENS_PER_ENTRY
+            );
+        });
+    }
+
+    #[test]
+    fn tool_entries_carry_their_call_id() {
+        let chat = ChatLog::new().tool("call_abc123", "Sunny");
+
+        assert_eq!(
+            serde_json::to_value(&chat).unwrap(),
+            serde_json::json!([{
+                "role": "tool",
+                "content": "Sunny",
+                "tool_call_id": "call_abc123",
+            }])
+        );
+
+        let without_id = ChatLog::new().add(ChatRole::Tool, "Sunny");
+        let id_tokens =
+            Tokenizer::with("gpt-4o", |tokenizer| tokenizer.count("call_abc123"));
+        assert_eq!(
+            chat.count_tokens("gpt-4o"),
+            without_id.count_tokens("gpt-4o") + id_tokens
+        );
+    }
+
+    #[test]
+    fn locks_the_tokenizer_once_per_log() {
+        let entries: Vec<ChatEntry> = serde_json::from_str(TOOL_CALL_FIXTURE).unwrap();
+        let log = ChatLog::new()
+            .system("You are helpful.")
+            .user("Hi there")
+            .assistant("Hello! How can I help?")
+            .concat(ChatLog(entries));
+
+        let before = TOKENIZER_LOCKS.with(std::cell::Cell::get);
+        log.count_tokens("gpt-4o");
+        assert_eq!(TOKENIZER_LOCKS.with(std::cell::Cell::get) - before, 1);
+    }
+
+    #[test]
+    fn counts_every_entry_of_a_log() {
+        let entries: Vec<ChatEntry> = serde_json::from_str(TOOL_CALL_FIXTURE).unwrap();
+        let separate = Tokenizer::with("gpt-4o", |tokenizer| {
+            entries
+                .iter()
+                .map(|entry| entry.count_tokens(tokenizer))
+                .sum::<usize>()
+        });
+
+        assert_eq!(
+            ChatLog(entries).count_tokens("gpt-4o"),
+            separate + TOKENS_PER_REPLY
+        );
+    }
+
+    #[test]
+    fn marks_a_leading_system_message() {
+        let chat = ChatLog::new().system("prompt").user("hi").system("later");
+        let body = ChatCompletionRequest::new(chat, &options(), CacheHints::Marker)
+            .to_body(CacheHints::Marker)
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(body["messages"][0]["content"][0]["text"], "prompt");
+        assert_eq!(
+            body["messages"][0]["content"][0]["cache_control"]["type"],
+            "ephemeral"
+        );
+        assert_eq!(body["messages"][2]["content"], "later");
+    }
+
+    #[test]
+    fn does_not_mark_a_system_message_after_the_start() {
+        let chat = ChatLog::new().user("hi").system("prompt");
+        let body = ChatCompletionRequest::new(chat, &options(), CacheHints::Marker)
+            .to_body(CacheHints::Marker)
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(body["messages"][1]["content"], "prompt");
+    }
+
+    #[tokio::test]
+    async fn completes_a_chat() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(completion_body("Hi")),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let completion = ChatLog::new()
+            .user("Hello")
+            .complete(&client(&server), &options())
+            .await
+            .unwrap();
+
+        assert_eq!(completion.message.content, "Hi");
+        assert_eq!(completion.usage.total_tokens, 12);
+        assert_eq!(completion.model, "gpt-4o");
+    }
+
+    #[tokio::test]
+    async fn marks_answers_from_the_cache() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(completion_body("Hi")),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = client(&server).cache(Some(ResponseCache::new(
+            Duration::from_secs(60),
+            10,
+            true,
+        )));
+        let chat = ChatLog::new().user("Hello");
+
+        let first = chat.clone().complete(&client, &options()).await.unwrap();
+        let second = chat.complete(&client, &options()).await.unwrap();
+
+        assert!(!first.cached);
+        assert!(second.cached);
+        assert_eq!(second.message.content, "Hi");
+    }
+
+    #[tokio::test]
+    async fn sends_the_model_messages_and_options() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(completion_body("Hi")),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let options = CompletionOptions {
+            temperature: Some(0.5),
+            user: Some("user-abc".to_string()),
+            ..options()
+        };
+        ChatLog::new()
+            .system("Be brief")
+            .user("Hello")
+            .complete(&client(&server), &options)
+            .await
+            .unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let body: serde_json::Value = requests[0].body_json().unwrap();
+        assert_eq!(body["model"], "gpt-4o");
+        assert_eq!(
+            body["messages"],
+            serde_json::json!([
+                { "role": "system", "content": "Be brief" },
+                { "role": "user", "content": "Hello" },
+            ])
+        );
+        assert_eq!(body["temperature"], 0.5);
+        assert_eq!(body["user"], "user-abc");
+    }
+
+    #[tokio::test]
+    async fn malformed_body_becomes_a_parse_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string("{\"choices\": [{\"content\": \"private answer"),
+            )
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        // The body may hold the content of a chat, so it is left out by default
+        let result = ChatLog::new()
+            .user("Hello")
+            .complete(&client(&server), &options())
+            .await;
+        match result {
+            Err(CompletionError::Parse(message)) => {
+                assert!(!message.contains("private answer"));
+            }
+            other => panic!("Expected a parse error, got {other:?}"),
+        }
+
+        let result = ChatLog::new()
+            .user("Hello")
+            .complete(&client(&server).log_raw(true), &options())
+            .await;
+        match result {
+            Err(CompletionError::Parse(message)) => {
+                assert!(message.contains("private answer"));
+            }
+            other => panic!("Expected a parse error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn error_body_becomes_a_status_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(
+                ResponseTemplate::new(400)
+                    .set_body_string(r#"{"error":{"message":"Bad request"}}"#),
+            )
+            .mount(&server)
+            .await;
+
+        let error = ChatLog::new()
+            .user("Hello")
+            .complete(&client(&server), &options())
+            .await
+            .unwrap_err();
+
+        match error {
+            CompletionError::Status(status, body) => {
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert!(body.contains("Bad request"));
+            }
+            other => panic!("Expected a status error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_with_the_same_idempotency_key() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(completion_body("Hi")),
+            )
+            .mount(&server)
+            .await;
+
+        let start = Instant::now();
+        let response = client(&server)
+            .retries(1)
+            .complete_chat_with_retry(&ChatLog::new().user("Hello"), &options())
+            .await
+            .unwrap();
+
+        assert_eq!(response.first_message().unwrap().content, "Hi");
+        // The first retry waits a second
+        assert!(start.elapsed() >= Duration::from_secs(1));
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[0].headers.get("Idempotency-Key"),
+            requests[1].headers.get("Idempotency-Key")
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let result = client(&server)
+            .retries(3)
+            .complete_chat_with_retry(&ChatLog::new().user("Hello"), &options())
+            .await;
+
+        assert!(matches!(result, Err(CompletionError::Status(status, _))
+            if status == StatusCode::UNAUTHORIZED));
     }
 }